@@ -0,0 +1,1263 @@
+//! Fetches the transactions of an ICRC-1 ledger and of its archives, and
+//! converts accounts to and from their textual encodings.
+
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    future::Future,
+    io::Write,
+    ops::ControlFlow,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use candid::{
+    parser::{
+        types::IDLProg,
+        typing::{check_prog, TypeEnv},
+        value::{IDLArgs, IDLValue},
+    },
+    types::{Label, Type},
+    CandidType, Decode, Encode, Nat, Principal,
+};
+use futures::{stream, StreamExt, TryStreamExt};
+use ic_agent::{Agent, AgentError};
+use ic_icrc1::{
+    endpoints::{
+        ArchivedTransactionRange, GetTransactionsRequest, GetTransactionsResponse, TransactionRange,
+    },
+    Account, Memo,
+};
+use num_traits::ToPrimitive;
+use serde::de::DeserializeOwned;
+use serde_bytes::ByteBuf;
+
+/// Archive methods known to return a `TransactionRange`.
+const ARCHIVE_METHODS: &[&str] = &["get_transactions"];
+
+/// Metadata of the ledger token.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub decimals: u8,
+    pub symbol: String,
+}
+
+/// A block of the ledger, decoded from the `ic_icrc1` types or, with
+/// `LedgerOptions::interface`, from the candid interface of the ledger.
+#[derive(Clone, Debug)]
+pub enum Transaction {
+    Burn {
+        timestamp: u64,
+        from: Account,
+        amount: Nat,
+        memo: Option<Memo>,
+        created_at_time: Option<u64>,
+    },
+    Mint {
+        timestamp: u64,
+        to: Account,
+        amount: Nat,
+        memo: Option<Memo>,
+        created_at_time: Option<u64>,
+    },
+    Transfer {
+        timestamp: u64,
+        from: Account,
+        to: Account,
+        amount: Nat,
+        fee: Option<Nat>,
+        memo: Option<Memo>,
+        created_at_time: Option<u64>,
+    },
+    Approve {
+        timestamp: u64,
+        from: Account,
+        spender: Account,
+        amount: Nat,
+        expected_allowance: Option<Nat>,
+        expires_at: Option<u64>,
+        fee: Option<Nat>,
+        memo: Option<Memo>,
+        created_at_time: Option<u64>,
+    },
+}
+
+impl Transaction {
+    pub fn get_kind(&self) -> &str {
+        match self {
+            Transaction::Burn { .. } => "burn",
+            Transaction::Mint { .. } => "mint",
+            Transaction::Transfer { .. } => "transfer",
+            Transaction::Approve { .. } => "approve",
+        }
+    }
+
+    pub fn get_timestamp(&self) -> u64 {
+        match self {
+            Transaction::Burn { timestamp, .. } => *timestamp,
+            Transaction::Mint { timestamp, .. } => *timestamp,
+            Transaction::Transfer { timestamp, .. } => *timestamp,
+            Transaction::Approve { timestamp, .. } => *timestamp,
+        }
+    }
+
+    pub fn get_from_account(&self) -> Option<&Account> {
+        match self {
+            Transaction::Burn { from, .. } => Some(from),
+            Transaction::Mint { .. } => None,
+            Transaction::Transfer { from, .. } => Some(from),
+            Transaction::Approve { from, .. } => Some(from),
+        }
+    }
+
+    /// Approvals move no tokens to the spender, which isn't a destination.
+    pub fn get_to_account(&self) -> Option<&Account> {
+        match self {
+            Transaction::Burn { .. } => None,
+            Transaction::Mint { to, .. } => Some(to),
+            Transaction::Transfer { to, .. } => Some(to),
+            Transaction::Approve { .. } => None,
+        }
+    }
+
+    pub fn get_spender(&self) -> Option<&Account> {
+        match self {
+            Transaction::Approve { spender, .. } => Some(spender),
+            _ => None,
+        }
+    }
+
+    pub fn get_amount(&self) -> Nat {
+        match self {
+            Transaction::Burn { amount, .. } => amount.clone(),
+            Transaction::Mint { amount, .. } => amount.clone(),
+            Transaction::Transfer { amount, .. } => amount.clone(),
+            Transaction::Approve { amount, .. } => amount.clone(),
+        }
+    }
+
+    pub fn get_fee(&self) -> Option<&Nat> {
+        match self {
+            Transaction::Burn { .. } => None,
+            Transaction::Mint { .. } => None,
+            Transaction::Transfer { fee, .. } => fee.as_ref(),
+            Transaction::Approve { fee, .. } => fee.as_ref(),
+        }
+    }
+
+    pub fn get_memo(&self) -> Option<&Memo> {
+        match self {
+            Transaction::Burn { memo, .. } => memo.as_ref(),
+            Transaction::Mint { memo, .. } => memo.as_ref(),
+            Transaction::Transfer { memo, .. } => memo.as_ref(),
+            Transaction::Approve { memo, .. } => memo.as_ref(),
+        }
+    }
+
+    pub fn get_created_at_time(&self) -> Option<&u64> {
+        match self {
+            Transaction::Burn {
+                created_at_time, ..
+            } => created_at_time.as_ref(),
+            Transaction::Mint {
+                created_at_time, ..
+            } => created_at_time.as_ref(),
+            Transaction::Transfer {
+                created_at_time, ..
+            } => created_at_time.as_ref(),
+            Transaction::Approve {
+                created_at_time, ..
+            } => created_at_time.as_ref(),
+        }
+    }
+}
+
+impl TryFrom<ic_icrc1::endpoints::Transaction> for Transaction {
+    type Error = String;
+
+    fn try_from(tx: ic_icrc1::endpoints::Transaction) -> Result<Self, Self::Error> {
+        match tx.kind.as_str() {
+            "mint" => {
+                let mint = tx
+                    .mint
+                    .ok_or_else(|| format!("Missing mint of kind {}", tx.kind))?;
+                Ok(Self::Mint {
+                    timestamp: tx.timestamp,
+                    to: mint.to,
+                    amount: mint.amount,
+                    memo: mint.memo,
+                    created_at_time: mint.created_at_time,
+                })
+            }
+            "burn" => {
+                let burn = tx
+                    .burn
+                    .ok_or_else(|| format!("Missing burn of kind {}", tx.kind))?;
+                Ok(Self::Burn {
+                    timestamp: tx.timestamp,
+                    from: burn.from,
+                    amount: burn.amount,
+                    memo: burn.memo,
+                    created_at_time: burn.created_at_time,
+                })
+            }
+            "transfer" => {
+                let transfer = tx
+                    .transfer
+                    .ok_or_else(|| format!("Missing transfer of kind {}", tx.kind))?;
+                Ok(Self::Transfer {
+                    timestamp: tx.timestamp,
+                    from: transfer.from,
+                    to: transfer.to,
+                    amount: transfer.amount,
+                    fee: transfer.fee,
+                    memo: transfer.memo,
+                    created_at_time: transfer.created_at_time,
+                })
+            }
+            "approve" => {
+                let approve = tx
+                    .approve
+                    .ok_or_else(|| format!("Missing approve of kind {}", tx.kind))?;
+                Ok(Self::Approve {
+                    timestamp: tx.timestamp,
+                    from: approve.from,
+                    spender: approve.spender,
+                    amount: approve.amount,
+                    expected_allowance: approve.expected_allowance,
+                    expires_at: approve.expires_at,
+                    fee: approve.fee,
+                    memo: approve.memo,
+                    created_at_time: approve.created_at_time,
+                })
+            }
+            _ => Err(format!("Unknown kind {}", tx.kind)),
+        }
+    }
+}
+
+/// Transactions numbered by block index, with the decode error of the
+/// transactions that couldn't be decoded.
+pub type Batch = Vec<(u64, Result<Transaction, String>)>;
+
+/// Unexpected but recoverable conditions met while fetching the blocks.
+#[derive(Clone, Debug)]
+pub enum Warning {
+    /// An archive must be queried with a method not known to return a `TransactionRange`.
+    UnexpectedArchiveMethod {
+        canister_id: Principal,
+        method: String,
+    },
+    /// The blocks in `[start, end)` are missing from the responses.
+    MissingBlocks { start: u64, end: u64 },
+    /// `fetch_range` didn't fetch as many blocks as requested.
+    UnexpectedLength {
+        start: u64,
+        requested: u64,
+        fetched: u64,
+    },
+    /// A query failed with a transient error and is retried after `delay`.
+    Retrying { error: String, delay: Duration },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::UnexpectedArchiveMethod {
+                canister_id,
+                method,
+            } => write!(f, "Unexpected archive method {}.{}", canister_id, method),
+            Warning::MissingBlocks { start, end } => write!(
+                f,
+                "Blocks {} to {} are missing from the responses",
+                start,
+                end - 1
+            ),
+            Warning::UnexpectedLength {
+                start,
+                requested,
+                fetched,
+            } => write!(
+                f,
+                "Fetched {} blocks instead of the {} requested from {}",
+                fetched, requested, start
+            ),
+            Warning::Retrying { error, delay } => {
+                write!(f, "{}, retrying in {}ms", error, delay.as_millis())
+            }
+        }
+    }
+}
+
+/// Called with each warning. An error returned by the handler fails the call
+/// that raised the warning.
+pub type WarningHandler = Box<dyn Fn(&Warning) -> Result<()> + Send + Sync>;
+
+/// Client of an ICRC-1 ledger and of its archives.
+pub struct LedgerClient {
+    agent: Agent,
+    archive_agent: Agent,
+    canister_id: Principal,
+    raw_responses_dir: Option<PathBuf>,
+    on_warning: Option<WarningHandler>,
+    retry: RetryPolicy,
+    concurrency: usize,
+    interface: Option<Interface>,
+    /// Highest log length returned by the ledger so far.
+    max_log_length: AtomicU64,
+}
+
+/// Settings of a `LedgerClient`.
+pub struct LedgerOptions {
+    /// Agent used to query the archives, defaults to the agent of the ledger.
+    pub archive_agent: Option<Agent>,
+    /// Directory the raw candid responses are written to.
+    pub raw_responses_dir: Option<PathBuf>,
+    /// Handler of the warnings, which are ignored by default.
+    pub on_warning: Option<WarningHandler>,
+    pub retry: RetryPolicy,
+    /// Maximum number of archive ranges fetched in parallel.
+    pub concurrency: usize,
+    /// Decode the responses with this interface instead of the `ic_icrc1` types.
+    pub interface: Option<Interface>,
+}
+
+impl Default for LedgerOptions {
+    fn default() -> Self {
+        Self {
+            archive_agent: None,
+            raw_responses_dir: None,
+            on_warning: None,
+            retry: RetryPolicy::default(),
+            concurrency: 1,
+            interface: None,
+        }
+    }
+}
+
+impl LedgerClient {
+    pub fn new(agent: Agent, canister_id: Principal) -> Self {
+        Self::with_options(agent, canister_id, LedgerOptions::default())
+    }
+
+    pub fn with_options(agent: Agent, canister_id: Principal, options: LedgerOptions) -> Self {
+        Self {
+            archive_agent: options.archive_agent.unwrap_or_else(|| agent.clone()),
+            agent,
+            canister_id,
+            raw_responses_dir: options.raw_responses_dir,
+            on_warning: options.on_warning,
+            retry: options.retry,
+            concurrency: options.concurrency,
+            interface: options.interface,
+            max_log_length: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn balance_of(&self, account: &Account) -> Result<Nat> {
+        let arg = Encode!(account)?;
+        let res = self
+            .with_retries(|| {
+                self.agent
+                    .query(&self.canister_id, "icrc1_balance_of")
+                    .with_arg(arg.clone())
+                    .call()
+            })
+            .await
+            .with_context(|| {
+                format!("Error while calling {}.icrc1_balance_of", self.canister_id)
+            })?;
+        Decode!(&res, Nat).with_context(|| {
+            format!(
+                "Cannot decode the response of {}.icrc1_balance_of",
+                self.canister_id
+            )
+        })
+    }
+
+    pub async fn token(&self) -> Result<Token> {
+        Ok(Token {
+            decimals: self.query_metadata("icrc1_decimals").await?,
+            symbol: self.query_metadata("icrc1_symbol").await?,
+        })
+    }
+
+    async fn query_metadata<T: CandidType + DeserializeOwned>(&self, method: &str) -> Result<T> {
+        let arg = Encode!()?;
+        let res = self
+            .with_retries(|| {
+                self.agent
+                    .query(&self.canister_id, method)
+                    .with_arg(arg.clone())
+                    .call()
+            })
+            .await
+            .with_context(|| format!("Error while calling {}.{}", self.canister_id, method))?;
+        Decode!(&res, T).with_context(|| {
+            format!(
+                "Cannot decode the response of {}.{}",
+                self.canister_id, method
+            )
+        })
+    }
+
+    pub async fn log_length(&self) -> Result<u64> {
+        if let Some(interface) = &self.interface {
+            let res = self
+                .query_transactions(&self.agent, &self.canister_id, "get_transactions", 0, 1)
+                .await?;
+            let res = interface.decode_get_transactions(&res)?;
+            return self.check_log_length_dynamic(&res);
+        }
+        nat_to_u64(&self.get_transactions(0, 1).await?.log_length)
+    }
+
+    async fn get_transactions(&self, start: u64, length: u64) -> Result<GetTransactionsResponse> {
+        let res = self
+            .query_transactions(
+                &self.agent,
+                &self.canister_id,
+                "get_transactions",
+                start,
+                length,
+            )
+            .await?;
+        let res = Decode!(&res, GetTransactionsResponse).with_context(|| {
+            format!(
+                "Cannot decode the response of {}.get_transactions",
+                self.canister_id
+            )
+        })?;
+        self.check_log_length(nat_to_u64(&res.log_length)?)?;
+        Ok(res)
+    }
+
+    /// The ledger is append-only so its length can only grow. If it shrinks
+    /// the ledger was reset under us and the block indices are meaningless.
+    fn check_log_length(&self, log_length: u64) -> Result<()> {
+        let max_log_length = self.max_log_length.fetch_max(log_length, Ordering::Relaxed);
+        if log_length < max_log_length {
+            bail!(
+                "Ledger length decreased from {} to {}, the ledger was possibly reinstalled",
+                max_log_length,
+                log_length
+            );
+        }
+        Ok(())
+    }
+
+    fn check_log_length_dynamic(&self, res: &IDLValue) -> Result<u64> {
+        let log_length = idl_field(res, "log_length")
+            .and_then(idl_to_u64)
+            .map_err(|e| anyhow!("Cannot read the log length: {}", e))?;
+        self.check_log_length(log_length)?;
+        Ok(log_length)
+    }
+
+    /// Returns the index of the first transaction of the archived range along
+    /// with the transactions.
+    async fn get_archived_transactions(
+        &self,
+        range: &ArchivedTransactionRange,
+    ) -> Result<(u64, Vec<ic_icrc1::endpoints::Transaction>)> {
+        let canister_id = range.callback.canister_id.get().0;
+        let method = &range.callback.method;
+        self.check_archive_method(&canister_id, method)?;
+        let start = nat_to_u64(&range.start)?;
+        let length = nat_to_u64(&range.length)?;
+        let transactions = self
+            .query_archived_range(&canister_id, method, start, length, |res| {
+                let res = Decode!(res, TransactionRange).with_context(|| {
+                    format!("Cannot decode the response of {}.{}", canister_id, method)
+                })?;
+                Ok(res.transactions)
+            })
+            .await?;
+        Ok((start, transactions))
+    }
+
+    /// Archives may return fewer transactions than requested, in which case
+    /// the rest of the range is requested again until the archive is empty.
+    async fn query_archived_range<T>(
+        &self,
+        canister_id: &Principal,
+        method: &str,
+        start: u64,
+        length: u64,
+        decode: impl Fn(&[u8]) -> Result<Vec<T>>,
+    ) -> Result<Vec<T>> {
+        let end = start
+            .checked_add(length)
+            .ok_or_else(|| anyhow!("Invalid archived range of {} blocks from {}", length, start))?;
+        let mut start = start;
+        let mut transactions = vec![];
+        while start < end {
+            let res = self
+                .query_transactions(&self.archive_agent, canister_id, method, start, end - start)
+                .await?;
+            let txs = decode(&res)?;
+            if txs.is_empty() {
+                break;
+            }
+            start += txs.len() as u64;
+            transactions.extend(txs);
+        }
+        Ok(transactions)
+    }
+
+    fn check_archive_method(&self, canister_id: &Principal, method: &str) -> Result<()> {
+        if !ARCHIVE_METHODS.contains(&method) {
+            self.warn(Warning::UnexpectedArchiveMethod {
+                canister_id: *canister_id,
+                method: method.to_string(),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn warn(&self, warning: Warning) -> Result<()> {
+        match &self.on_warning {
+            Some(on_warning) => on_warning(&warning),
+            None => Ok(()),
+        }
+    }
+
+    async fn query_transactions(
+        &self,
+        agent: &Agent,
+        canister_id: &Principal,
+        method: &str,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<u8>> {
+        let req = GetTransactionsRequest {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        };
+        let arg = Encode!(&req)?;
+        let res = self
+            .with_retries(|| {
+                agent
+                    .query(canister_id, method)
+                    .with_arg(arg.clone())
+                    .call()
+            })
+            .await
+            .with_context(|| format!("Error while calling {}.{}", canister_id, method))?;
+        if let Some(dir) = &self.raw_responses_dir {
+            dump_raw_response(dir, canister_id, method, start, length, &res)?;
+        }
+        Ok(res)
+    }
+
+    /// Runs `query` with a deadline of `retry.timeout`, retrying it as long as it
+    /// fails with a transient error and `retry.max_retries` isn't reached.
+    async fn with_retries<F, Fut>(&self, mut query: F) -> Result<Vec<u8>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, AgentError>>,
+    {
+        let retry = &self.retry;
+        let mut retries = 0;
+        loop {
+            // A replica that never answers would otherwise hang the run.
+            let res = match tokio::time::timeout(retry.timeout, query()).await {
+                Ok(res) => res,
+                Err(_) => Err(AgentError::TimeoutWaitingForResponse()),
+            };
+            match res {
+                Err(e) if retries < retry.max_retries && is_transient(&e) => {
+                    let delay = retry.delay(retries);
+                    self.warn(Warning::Retrying {
+                        error: e.to_string(),
+                        delay,
+                    })?;
+                    tokio::time::sleep(delay).await;
+                    retries += 1;
+                }
+                res => return Ok(res?),
+            }
+        }
+    }
+
+    /// Fetches the blocks in `[start, start + length)`, or up to the end of
+    /// the log, and returns the first decode error if any.
+    pub async fn fetch_transactions(
+        &self,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<(u64, Transaction)>> {
+        let mut txs = vec![];
+        self.fetch_range(start, length, |batch| {
+            for (idx, tx) in batch {
+                let tx = tx.map_err(|e| anyhow!("Cannot decode tx {}: {}", idx, e))?;
+                txs.push((idx, tx));
+            }
+            Ok(ControlFlow::Continue(()))
+        })
+        .await?;
+        Ok(txs)
+    }
+
+    /// Fetches the blocks in `[start, start + length)`, or up to the end of the
+    /// log, page by page. The ledger caps the number of blocks returned by a
+    /// single `get_transactions` call, so the range is requested again from
+    /// the first block that wasn't returned until it is exhausted.
+    pub async fn fetch_range(
+        &self,
+        start: u64,
+        length: u64,
+        mut on_batch: impl FnMut(Batch) -> Result<ControlFlow<()>>,
+    ) -> Result<()> {
+        let end = start
+            .checked_add(length)
+            .ok_or_else(|| anyhow!("{} blocks from {} overflow the block index", length, start))?;
+        let mut idx = start;
+        let mut fetched = 0;
+        while idx < end {
+            let mut next = idx;
+            for batch in self.fetch_txs(idx, end - idx).await? {
+                let (first, last) = match (batch.first(), batch.last()) {
+                    (Some((first, _)), Some((last, _))) => (*first, *last),
+                    _ => continue,
+                };
+                if first > next {
+                    self.warn(Warning::MissingBlocks {
+                        start: next,
+                        end: first,
+                    })?;
+                }
+                next = next.max(last + 1);
+                fetched += batch.len() as u64;
+                if on_batch(batch)?.is_break() {
+                    return Ok(());
+                }
+            }
+            if next == idx {
+                break;
+            }
+            idx = next;
+        }
+        if fetched != length {
+            self.warn(Warning::UnexpectedLength {
+                start,
+                requested: length,
+                fetched,
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Fetches a single page of the blocks in `[start, start + length)`, one
+    /// batch per archive range followed by one batch for the blocks still held
+    /// by the ledger. Each block is numbered from the start of the range it
+    /// was returned in, so that a short or missing range can't shift the
+    /// indices of the following ones.
+    async fn fetch_txs(&self, start: u64, length: u64) -> Result<Vec<Batch>> {
+        if let Some(interface) = &self.interface {
+            return self.fetch_txs_dynamic(interface, start, length).await;
+        }
+        let res = self.get_transactions(start, length).await?;
+
+        // `buffered` yields the ranges in order even if they complete out of order.
+        let archived: Vec<_> = stream::iter(&res.archived_transactions)
+            .map(|range| self.get_archived_transactions(range))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut batches = vec![];
+        for (range_start, txs) in archived {
+            let txs = txs.into_iter().map(TryInto::try_into);
+            batches.push((range_start..).zip(txs).collect());
+        }
+
+        let first_index = nat_to_u64(&res.first_index)?;
+        let txs = res.transactions.into_iter().map(TryInto::try_into);
+        batches.push((first_index..).zip(txs).collect());
+        Ok(batches)
+    }
+
+    /// Same as `fetch_txs` but decodes the responses with the interface of the
+    /// ledger instead of the compiled-in types.
+    async fn fetch_txs_dynamic(
+        &self,
+        interface: &Interface,
+        start: u64,
+        length: u64,
+    ) -> Result<Vec<Batch>> {
+        let res = self
+            .query_transactions(
+                &self.agent,
+                &self.canister_id,
+                "get_transactions",
+                start,
+                length,
+            )
+            .await?;
+        let res = interface.decode_get_transactions(&res)?;
+        self.check_log_length_dynamic(&res)?;
+        let ranges = idl_field(&res, "archived_transactions")
+            .and_then(idl_to_vec)
+            .map_err(|e| anyhow!("Cannot read the archived transactions: {}", e))?;
+
+        let archived: Vec<_> = stream::iter(ranges)
+            .map(|range| self.get_archived_transactions_dynamic(range))
+            .buffered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut batches = vec![];
+        for (range_start, txs) in archived {
+            batches.push((range_start..).zip(txs).collect());
+        }
+
+        let first_index = idl_field(&res, "first_index")
+            .and_then(idl_to_u64)
+            .map_err(|e| anyhow!("Cannot read the first index: {}", e))?;
+        batches.push((first_index..).zip(idl_to_txs(&res)?).collect());
+        Ok(batches)
+    }
+
+    /// Same as `get_archived_transactions` for an archived range decoded with
+    /// the interface of the ledger.
+    async fn get_archived_transactions_dynamic(
+        &self,
+        range: &IDLValue,
+    ) -> Result<(u64, Vec<Result<Transaction, String>>)> {
+        let (canister_id, method) = match idl_field(range, "callback") {
+            Ok(IDLValue::Func(canister_id, method)) => (*canister_id, method.as_str()),
+            _ => bail!("Cannot read the callback of the archived range {}", range),
+        };
+        self.check_archive_method(&canister_id, method)?;
+        let read = |field: &str| {
+            idl_field(range, field)
+                .and_then(idl_to_u64)
+                .map_err(|e| anyhow!("Cannot read the archived range {}: {}", range, e))
+        };
+        let range_start = read("start")?;
+        let transactions = self
+            .query_archived_range(&canister_id, method, range_start, read("length")?, |res| {
+                idl_to_txs(&decode_archive_response(res)?)
+            })
+            .await?;
+        Ok((range_start, transactions))
+    }
+}
+
+/// Exponential backoff of the queries failing with a transient error.
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each retry.
+    pub base_delay: Duration,
+    /// Deadline of each query, a query past it fails with a transient error.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay doubles after each retry and is then jittered down by up to
+    /// half so that concurrent runs don't hammer the replica in lockstep.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.base_delay.saturating_mul(1 << retry.min(16));
+        let half = delay.as_millis() as u64 / 2;
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        let jitter = SplitMix64(seed as u64).next_u64() % (half + 1);
+        delay - Duration::from_millis(jitter)
+    }
+}
+
+/// Replica 5xx, connection errors and timeouts may succeed if retried.
+/// Rejects by the canister and everything else are permanent.
+fn is_transient(e: &AgentError) -> bool {
+    match e {
+        AgentError::TransportError(_) | AgentError::TimeoutWaitingForResponse() => true,
+        AgentError::HttpError(payload) => payload.status >= 500,
+        _ => false,
+    }
+}
+
+/// Candid interface of the ledger, used to decode the
+/// `get_transactions` responses even if they differ from the `ic_icrc1` types.
+pub struct Interface {
+    env: TypeEnv,
+    get_transactions: Vec<Type>,
+}
+
+impl Interface {
+    pub async fn fetch(agent: &Agent, canister_id: &Principal) -> Result<Self> {
+        let res = agent
+            .query(canister_id, "__get_candid_interface_tmp_hack")
+            .with_arg(Encode!()?)
+            .call()
+            .await
+            .with_context(|| {
+                format!(
+                    "Error while calling {}.__get_candid_interface_tmp_hack",
+                    canister_id
+                )
+            })?;
+        let did = Decode!(&res, String).with_context(|| {
+            format!(
+                "Cannot decode the response of {}.__get_candid_interface_tmp_hack",
+                canister_id
+            )
+        })?;
+        let prog: IDLProg = did
+            .parse()
+            .with_context(|| format!("Cannot parse the interface of {}", canister_id))?;
+        let mut env = TypeEnv::new();
+        let actor = check_prog(&mut env, &prog)
+            .with_context(|| format!("Cannot check the interface of {}", canister_id))?
+            .ok_or_else(|| anyhow!("The interface of {} has no service", canister_id))?;
+        let get_transactions = env
+            .get_method(&actor, "get_transactions")
+            .with_context(|| format!("Cannot find {}.get_transactions", canister_id))?
+            .rets
+            .clone();
+        Ok(Self {
+            env,
+            get_transactions,
+        })
+    }
+
+    fn decode_get_transactions(&self, res: &[u8]) -> Result<IDLValue> {
+        let mut res = IDLArgs::from_bytes_with_types(res, &self.env, &self.get_transactions)
+            .context("Cannot decode the get_transactions response")?;
+        if res.args.is_empty() {
+            bail!("The get_transactions response is empty");
+        }
+        Ok(res.args.swap_remove(0))
+    }
+}
+
+/// Decodes an archive response with the types it carries. Field names are
+/// then only known by their hash, which `idl_field` takes into account.
+fn decode_archive_response(res: &[u8]) -> Result<IDLValue> {
+    let mut res = IDLArgs::from_bytes(res).context("Cannot decode the archive response")?;
+    if res.args.is_empty() {
+        bail!("The archive response is empty");
+    }
+    Ok(res.args.swap_remove(0))
+}
+
+fn idl_field<'a>(value: &'a IDLValue, name: &str) -> Result<&'a IDLValue, String> {
+    let id = Label::Named(name.to_string()).get_id();
+    match value {
+        IDLValue::Record(fields) => fields
+            .iter()
+            .find(|field| field.id.get_id() == id)
+            .map(|field| &field.val)
+            .ok_or_else(|| format!("Missing field {}", name)),
+        _ => Err(format!("Expected a record with field {}", name)),
+    }
+}
+
+/// Missing optional fields are treated as `null`.
+fn idl_opt_field<'a>(value: &'a IDLValue, name: &str) -> Option<&'a IDLValue> {
+    match idl_field(value, name) {
+        Ok(IDLValue::Opt(value)) => Some(&**value),
+        _ => None,
+    }
+}
+
+fn idl_to_vec(value: &IDLValue) -> Result<&[IDLValue], String> {
+    match value {
+        IDLValue::Vec(values) => Ok(values.as_slice()),
+        _ => Err(format!("Expected a vec, got {}", value)),
+    }
+}
+
+fn idl_to_u64(value: &IDLValue) -> Result<u64, String> {
+    match value {
+        IDLValue::Nat64(n) => Ok(*n),
+        IDLValue::Nat(n) => n.0.to_u64().ok_or_else(|| format!("{} is not a u64", n)),
+        _ => Err(format!("Expected a nat64, got {}", value)),
+    }
+}
+
+fn idl_to_nat(value: &IDLValue) -> Result<Nat, String> {
+    match value {
+        IDLValue::Nat(n) => Ok(n.clone()),
+        IDLValue::Nat64(n) => Ok(Nat::from(*n)),
+        _ => Err(format!("Expected a nat, got {}", value)),
+    }
+}
+
+fn idl_to_bytes(value: &IDLValue) -> Result<Vec<u8>, String> {
+    idl_to_vec(value)?
+        .iter()
+        .map(|byte| match byte {
+            IDLValue::Nat8(byte) => Ok(*byte),
+            _ => Err(format!("Expected a blob, got {}", value)),
+        })
+        .collect()
+}
+
+fn idl_to_account(value: &IDLValue) -> Result<Account, String> {
+    let owner = match idl_field(value, "owner")? {
+        IDLValue::Principal(owner) => *owner,
+        owner => return Err(format!("Expected a principal, got {}", owner)),
+    };
+    let subaccount = match idl_opt_field(value, "subaccount") {
+        Some(subaccount) => Some(
+            idl_to_bytes(subaccount)?
+                .try_into()
+                .map_err(|_| format!("Subaccount {} is not 32 bytes", subaccount))?,
+        ),
+        None => None,
+    };
+    Ok(Account {
+        owner: owner.into(),
+        subaccount,
+    })
+}
+
+fn idl_to_transaction(value: &IDLValue) -> Result<Transaction, String> {
+    let kind = match idl_field(value, "kind")? {
+        IDLValue::Text(kind) => kind.as_str(),
+        kind => return Err(format!("Expected a text kind, got {}", kind)),
+    };
+    let timestamp = idl_to_u64(idl_field(value, "timestamp")?)?;
+    let op =
+        idl_opt_field(value, kind).ok_or_else(|| format!("Missing {} of kind {}", kind, kind))?;
+    let amount = idl_to_nat(idl_field(op, "amount")?)?;
+    let memo = match idl_opt_field(op, "memo") {
+        Some(memo) => Some(Memo::from(ByteBuf::from(idl_to_bytes(memo)?))),
+        None => None,
+    };
+    let created_at_time = match idl_opt_field(op, "created_at_time") {
+        Some(created_at_time) => Some(idl_to_u64(created_at_time)?),
+        None => None,
+    };
+    let fee = match idl_opt_field(op, "fee") {
+        Some(fee) => Some(idl_to_nat(fee)?),
+        None => None,
+    };
+    match kind {
+        "mint" => Ok(Transaction::Mint {
+            timestamp,
+            to: idl_to_account(idl_field(op, "to")?)?,
+            amount,
+            memo,
+            created_at_time,
+        }),
+        "burn" => Ok(Transaction::Burn {
+            timestamp,
+            from: idl_to_account(idl_field(op, "from")?)?,
+            amount,
+            memo,
+            created_at_time,
+        }),
+        "transfer" => Ok(Transaction::Transfer {
+            timestamp,
+            from: idl_to_account(idl_field(op, "from")?)?,
+            to: idl_to_account(idl_field(op, "to")?)?,
+            amount,
+            fee,
+            memo,
+            created_at_time,
+        }),
+        "approve" => Ok(Transaction::Approve {
+            timestamp,
+            from: idl_to_account(idl_field(op, "from")?)?,
+            spender: idl_to_account(idl_field(op, "spender")?)?,
+            amount,
+            expected_allowance: match idl_opt_field(op, "expected_allowance") {
+                Some(expected_allowance) => Some(idl_to_nat(expected_allowance)?),
+                None => None,
+            },
+            expires_at: match idl_opt_field(op, "expires_at") {
+                Some(expires_at) => Some(idl_to_u64(expires_at)?),
+                None => None,
+            },
+            fee,
+            memo,
+            created_at_time,
+        }),
+        _ => Err(format!("Unknown kind {}", kind)),
+    }
+}
+
+fn idl_to_txs(value: &IDLValue) -> Result<Vec<Result<Transaction, String>>> {
+    Ok(idl_field(value, "transactions")
+        .and_then(idl_to_vec)
+        .map_err(|e| anyhow!("Cannot read the transactions: {}", e))?
+        .iter()
+        .map(idl_to_transaction)
+        .collect())
+}
+
+fn nat_to_u64(n: &Nat) -> Result<u64> {
+    n.0.to_u64()
+        .ok_or_else(|| anyhow!("Cannot convert {} to u64", n))
+}
+
+/// Inverse of `parse_icrc1_account`. The default subaccount is omitted and
+/// the others are printed in lowercase hex without leading zeros.
+pub fn account_to_icrc1_str(account: &Account) -> String {
+    match account.subaccount {
+        Some(subaccount) if subaccount != [0; 32] => {
+            let hex: String = subaccount
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            format!(
+                "{}-{}.{}",
+                account.owner,
+                account_checksum(&account.owner.0, &subaccount),
+                hex.trim_start_matches('0')
+            )
+        }
+        _ => account.owner.to_string(),
+    }
+}
+
+/// Parses the ICRC-1 textual encoding of an account, i.e. either a principal
+/// for the default subaccount or `<principal>-<checksum>.<subaccount>` where
+/// the subaccount is in hex without leading zeros.
+pub fn parse_icrc1_account(text: &str) -> Result<Account, String> {
+    let (owner, subaccount) = match text.split_once('.') {
+        None => (text, None),
+        Some((owner_and_checksum, subaccount)) => {
+            let (owner, checksum) = owner_and_checksum
+                .rsplit_once('-')
+                .ok_or_else(|| format!("Missing checksum in account {}", text))?;
+            (owner, Some((checksum, subaccount)))
+        }
+    };
+    let owner = Principal::from_text(owner)
+        .map_err(|e| format!("Invalid owner in account {}: {}", text, e))?;
+    let subaccount = match subaccount {
+        None => None,
+        Some((checksum, subaccount)) => {
+            if subaccount.is_empty() || subaccount.starts_with('0') || subaccount.len() > 64 {
+                return Err(format!("Invalid subaccount in account {}", text));
+            }
+            let subaccount = hex_to_subaccount(&format!("{:0>64}", subaccount))?;
+            if checksum != account_checksum(&owner, &subaccount) {
+                return Err(format!("Invalid checksum in account {}", text));
+            }
+            Some(subaccount)
+        }
+    };
+    Ok(Account {
+        owner: owner.into(),
+        subaccount,
+    })
+}
+
+/// Parses `principal[.subaccount-hex]`, the subaccount being 32 bytes of hex.
+/// Unlike the ICRC-1 textual encoding it has no checksum and no compression.
+pub fn parse_account(text: &str) -> Result<Account, String> {
+    let (owner, subaccount) = match text.split_once('.') {
+        None => (text, None),
+        Some((owner, subaccount)) => (owner, Some(hex_to_subaccount(subaccount)?)),
+    };
+    let owner = Principal::from_text(owner)
+        .map_err(|e| format!("Invalid owner in account {}: {}", text, e))?;
+    Ok(Account {
+        owner: owner.into(),
+        subaccount,
+    })
+}
+
+fn hex_to_subaccount(hex: &str) -> Result<[u8; 32], String> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(format!("Subaccount {} is not 32 bytes of hex", hex));
+    }
+    let mut subaccount = [0; 32];
+    for (i, byte) in subaccount.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|e| format!("Invalid subaccount {}: {}", hex, e))?;
+    }
+    Ok(subaccount)
+}
+
+/// The checksum is the base32 encoded CRC32 of the owner and subaccount bytes.
+fn account_checksum(owner: &Principal, subaccount: &[u8; 32]) -> String {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    base32_encode(&hasher.finalize().to_be_bytes())
+}
+
+/// RFC 4648 base32 in lowercase and without padding.
+fn base32_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut res = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            res.push(ALPHABET[((buffer >> bits) & 0x1F) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        res.push(ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char);
+    }
+    res
+}
+
+/// Writes the candid encoded response to `<canister_id>_<start>_<length>.bin`
+/// in `dir` and records the request parameters in `dir/index.txt`.
+fn dump_raw_response(
+    dir: &Path,
+    canister_id: &Principal,
+    method: &str,
+    start: u64,
+    length: u64,
+    res: &[u8],
+) -> Result<()> {
+    let file_name = format!("{}_{}_{}.bin", canister_id, start, length);
+    let path = dir.join(&file_name);
+    fs::write(&path, res).with_context(|| format!("Cannot write {}", path.display()))?;
+    let index_path = dir.join("index.txt");
+    let mut index = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&index_path)
+        .with_context(|| format!("Cannot open {}", index_path.display()))?;
+    writeln!(
+        index,
+        "{}|{}|{}|{}|{}",
+        file_name, canister_id, method, start, length
+    )
+    .with_context(|| format!("Cannot write {}", index_path.display()))
+}
+
+/// Deterministic pseudo random number generator, the same seed always yields
+/// the same sequence. Used to jitter the retry delays and to pick samples.
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    fn account(subaccount: Option<[u8; 32]>) -> Account {
+        Account {
+            owner: Principal::from_text(OWNER).unwrap().into(),
+            subaccount,
+        }
+    }
+
+    #[test]
+    fn base32_encode_matches_rfc4648() {
+        let vectors = [
+            ("", ""),
+            ("f", "my"),
+            ("fo", "mzxq"),
+            ("foo", "mzxw6"),
+            ("foob", "mzxw6yq"),
+            ("fooba", "mzxw6ytb"),
+            ("foobar", "mzxw6ytboi"),
+        ];
+        for (bytes, encoded) in vectors {
+            assert_eq!(base32_encode(bytes.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn icrc1_account_matches_the_spec() {
+        let mut one = [0; 32];
+        one[31] = 1;
+        let mut counting = [0; 32];
+        for (i, byte) in counting.iter_mut().enumerate() {
+            *byte = i as u8 + 1;
+        }
+        let vectors = [
+            (None, OWNER.to_string()),
+            (Some([0; 32]), OWNER.to_string()),
+            (Some(one), format!("{}-6cc627i.1", OWNER)),
+            (
+                Some(counting),
+                format!(
+                    "{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20",
+                    OWNER
+                ),
+            ),
+        ];
+        for (subaccount, text) in vectors {
+            assert_eq!(account_to_icrc1_str(&account(subaccount)), text);
+        }
+    }
+
+    #[test]
+    fn icrc1_account_round_trips() {
+        let mut subaccount = [0; 32];
+        subaccount[0] = 0xff;
+        subaccount[17] = 0x42;
+        for account in [account(None), account(Some(subaccount))] {
+            let text = account_to_icrc1_str(&account);
+            assert_eq!(parse_icrc1_account(&text), Ok(account));
+        }
+    }
+
+    #[test]
+    fn icrc1_account_rejects_invalid_subaccounts() {
+        for text in [
+            format!("{}-6cc627j.1", OWNER),
+            format!("{}-6cc627i.01", OWNER),
+            format!("{}.1", OWNER),
+            format!("{}-6cc627i.", OWNER),
+        ] {
+            assert!(parse_icrc1_account(&text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_account_reads_an_optional_subaccount() {
+        assert_eq!(parse_account(OWNER), Ok(account(None)));
+        let mut subaccount = [0; 32];
+        subaccount[0] = 0xab;
+        subaccount[31] = 1;
+        let text = format!("{}.AB{}01", OWNER, "0".repeat(60));
+        assert_eq!(parse_account(&text), Ok(account(Some(subaccount))));
+        assert_eq!(
+            parse_account(&text.to_lowercase()),
+            Ok(account(Some(subaccount)))
+        );
+    }
+
+    #[test]
+    fn parse_account_rejects_invalid_accounts() {
+        for text in [
+            format!("{}.01", OWNER),
+            format!("{}.{}", OWNER, "0".repeat(66)),
+            format!("{}.{}", OWNER, "g".repeat(64)),
+            format!("{}.", OWNER),
+            "not-a-principal".to_string(),
+        ] {
+            assert!(parse_account(&text).is_err(), "{}", text);
+        }
+    }
+}